@@ -5,14 +5,23 @@ use futures_util::stream::StreamExt;
 use html_escape::encode_safe;
 use log::error;
 use mime_guess::mime;
-use std::io::Write;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use uuid::Uuid;
-use image;
 
 #[derive(Clone)]
 struct AppState {
     posts: Arc<Mutex<Vec<Post>>>,
+    jobs: Arc<Mutex<HashMap<Uuid, JobState>>>,
+    // Maps a content digest to the image URL already stored for it, for dedup.
+    digests: Arc<Mutex<HashMap<String, String>>>,
+}
+
+#[derive(Clone)]
+enum JobState {
+    Pending,
+    Done { post_id: Uuid },
+    Failed { reason: String },
 }
 
 #[derive(Clone)]
@@ -22,6 +31,7 @@ struct Post {
     subject: String,
     body: String,
     image_url: Option<String>,
+    thumb_url: Option<String>,
 }
 
 #[derive(Default)]
@@ -30,18 +40,28 @@ struct PostData {
     subject: String,
     body: String,
     image_path: Option<String>,
+    thumb_path: Option<String>,
 }
 
 const IMAGE_UPLOAD_DIR: &str = "./uploads/images/";
+const THUMB_UPLOAD_DIR: &str = "./uploads/thumbs/";
+const THUMB_MAX_DIMENSION: u32 = 200;
+
+const OUTPUT_EXTENSION: &str = "jpg";
+const OUTPUT_QUALITY: u8 = 85;
+const MAX_OUTPUT_DIMENSION: u32 = 4096;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     std::fs::create_dir_all(IMAGE_UPLOAD_DIR).ok();
+    std::fs::create_dir_all(THUMB_UPLOAD_DIR).ok();
 
     let state = AppState {
         posts: Arc::new(Mutex::new(Vec::new())),
+        jobs: Arc::new(Mutex::new(HashMap::new())),
+        digests: Arc::new(Mutex::new(HashMap::new())),
     };
 
     HttpServer::new(move || {
@@ -52,8 +72,15 @@ async fn main() -> std::io::Result<()> {
             .route("/", web::get().to(homepage))
             // Handle form posts
             .route("/post", web::post().to(handle_post))
+            // Same as /post, but returns immediately and finishes the image
+            // pipeline on a background task
+            .route("/post/backgrounded", web::post().to(handle_post_backgrounded))
+            // Poll the status of a backgrounded upload
+            .route("/job/{id}", web::get().to(job_status))
             // Serve uploaded images
             .service(Files::new("/uploads/images", "./uploads/images"))
+            // Serve generated thumbnails
+            .service(Files::new("/uploads/thumbs", "./uploads/thumbs"))
     })
     .bind(("0.0.0.0", 8080))?
     .run()
@@ -66,7 +93,12 @@ async fn homepage(state: web::Data<AppState>) -> HttpResponse {
     let mut posts_html = String::new();
     for post in posts.iter().rev() {
         let image_html = if let Some(url) = &post.image_url {
-            format!(r#"<div class="image"><img src="{}" alt="image" style="max-width:200px;"></div>"#, encode_html(url))
+            let thumb = post.thumb_url.as_deref().unwrap_or(url);
+            format!(
+                r#"<div class="image"><a href="{full}"><img src="{thumb}" alt="image" style="max-width:200px;"></a></div>"#,
+                full = encode_html(url),
+                thumb = encode_html(thumb)
+            )
         } else {
             "".to_string()
         };
@@ -102,7 +134,7 @@ async fn homepage(state: web::Data<AppState>) -> HttpResponse {
     <input type="text" name="name" placeholder="Name" required style="display:block;margin-bottom:10px;">
     <input type="text" name="subject" placeholder="Subject" required style="display:block;margin-bottom:10px;">
     <textarea name="body" rows="5" cols="35" placeholder="Comment" required style="display:block;width:300px;height:100px;margin-bottom:10px;"></textarea>
-    <input type="file" name="file" accept=".jpg,.jpeg,.png,.gif,.webp" style="display:block;margin-bottom:10px;">
+    <input type="file" name="file" accept=".jpg,.jpeg,.png,.gif,.webp,.avif,.jxl" style="display:block;margin-bottom:10px;">
     <input type="submit" value="Post">
 </form>
 <hr>
@@ -145,37 +177,18 @@ async fn handle_post(
 
         if field_name == "file" && !value.is_empty() {
             if let Some(fname) = filename {
-                let mime_type = mime_guess::from_path(&fname).first_or_octet_stream();
-                if mime_type.type_() == mime::IMAGE {
-                    if !matches!(mime_type.subtype().as_ref(), "jpeg" | "jpg" | "png" | "gif" | "webp") {
-                        return Ok(HttpResponse::BadRequest().body("Unsupported image format"));
+                let digests = state.digests.clone();
+                match web::block(move || process_image_upload(&fname, &value, &digests)).await {
+                    Ok(Some(Ok(saved))) => {
+                        post_data.image_path = Some(saved.image_url);
+                        post_data.thumb_path = saved.thumb_url;
                     }
-
-                    let unique_id = Uuid::new_v4().to_string();
-                    let extension = mime_type.subtype().as_str();
-                    let sanitized_filename = format!("{}.{}", unique_id, extension);
-                    let filepath = format!("{}{}", IMAGE_UPLOAD_DIR, sanitized_filename);
-                    let filepath_clone = filepath.clone();
-
-                    let mut f = match web::block(move || std::fs::File::create(&filepath)).await {
-                        Ok(Ok(file)) => file,
-                        _ => {
-                            log_error("Failed to create image file");
-                            return Ok(HttpResponse::InternalServerError().body("Failed to save image"));
-                        }
-                    };
-
-                    if let Err(e) = web::block(move || f.write_all(&value)).await {
-                        log_error(&format!("Error writing image: {}", e));
-                        return Ok(HttpResponse::InternalServerError().body("Failed to write image"));
-                    }
-
-                    if image::open(&filepath_clone).is_err() {
-                        std::fs::remove_file(&filepath_clone).ok();
-                        return Ok(HttpResponse::BadRequest().body("Invalid image file"));
+                    Ok(Some(Err(reason))) => return Ok(HttpResponse::BadRequest().body(reason)),
+                    Ok(None) => {}
+                    Err(e) => {
+                        log_error(&format!("Image processing task failed: {}", e));
+                        return Ok(HttpResponse::InternalServerError().body("Failed to save image"));
                     }
-
-                    post_data.image_path = Some(format!("/uploads/images/{}", sanitized_filename));
                 }
             }
         } else {
@@ -199,6 +212,7 @@ async fn handle_post(
         subject: post_data.subject,
         body: post_data.body,
         image_url: post_data.image_path,
+        thumb_url: post_data.thumb_path,
     };
 
     {
@@ -209,6 +223,286 @@ async fn handle_post(
     Ok(HttpResponse::SeeOther().append_header(("Location", "/")).finish())
 }
 
+// Same as handle_post, but the upload is processed in a background task;
+// returns a job id immediately and the client polls /job/{id} for completion.
+async fn handle_post_backgrounded(
+    state: web::Data<AppState>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let mut post_data = PostData::default();
+    let mut image_field: Option<(String, Vec<u8>)> = None;
+
+    while let Some(item) = payload.next().await {
+        let mut field = match item {
+            Ok(f) => f,
+            Err(e) => {
+                log_error(&format!("Error processing field: {}", e));
+                return Ok(HttpResponse::BadRequest().body("Invalid form data"));
+            }
+        };
+
+        let disp = field.content_disposition();
+        let field_name = disp.get_name().unwrap_or("").to_string();
+        let filename = disp.get_filename().map(|s| s.to_string());
+
+        let mut value = Vec::new();
+        while let Some(chunk) = field.next().await {
+            match chunk {
+                Ok(data) => value.extend_from_slice(&data),
+                Err(e) => {
+                    log_error(&format!("Error reading chunk: {}", e));
+                    return Ok(HttpResponse::BadRequest().body("Error reading form data"));
+                }
+            }
+        }
+
+        if field_name == "file" && !value.is_empty() {
+            if let Some(fname) = filename {
+                image_field = Some((fname, value));
+            }
+        } else {
+            let value_str = String::from_utf8_lossy(&value).to_string();
+            match field_name.as_str() {
+                "name" => post_data.name = value_str.trim().to_string(),
+                "subject" => post_data.subject = value_str.trim().to_string(),
+                "body" => post_data.body = value_str.trim().to_string(),
+                _ => {}
+            }
+        }
+    }
+
+    if post_data.name.is_empty() || post_data.subject.is_empty() || post_data.body.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("Name, Subject, and Body are required"));
+    }
+
+    let job_id = Uuid::new_v4();
+    state.jobs.lock().unwrap().insert(job_id, JobState::Pending);
+
+    let job_state_handle = state.clone();
+    actix_web::rt::spawn(async move {
+        let image_result: Result<Option<SavedImage>, String> = match image_field {
+            Some((fname, value)) => {
+                let digests = job_state_handle.digests.clone();
+                match web::block(move || process_image_upload(&fname, &value, &digests)).await {
+                    Ok(Some(Ok(saved))) => Ok(Some(saved)),
+                    Ok(Some(Err(reason))) => Err(reason),
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(format!("Image processing task failed: {}", e)),
+                }
+            }
+            None => Ok(None),
+        };
+
+        let job_state = match image_result {
+            Ok(saved) => {
+                let post = Post {
+                    id: Uuid::new_v4(),
+                    name: post_data.name,
+                    subject: post_data.subject,
+                    body: post_data.body,
+                    image_url: saved.as_ref().map(|s| s.image_url.clone()),
+                    thumb_url: saved.and_then(|s| s.thumb_url),
+                };
+                let post_id = post.id;
+                job_state_handle.posts.lock().unwrap().push(post);
+                JobState::Done { post_id }
+            }
+            Err(reason) => {
+                log_error(&format!("Backgrounded upload {} failed: {}", job_id, reason));
+                JobState::Failed { reason }
+            }
+        };
+
+        job_state_handle.jobs.lock().unwrap().insert(job_id, job_state);
+    });
+
+    Ok(HttpResponse::Accepted()
+        .append_header(("Location", format!("/job/{}", job_id)))
+        .content_type("application/json")
+        .body(format!(r#"{{"job_id":"{}"}}"#, job_id)))
+}
+
+async fn job_status(state: web::Data<AppState>, job_id: web::Path<Uuid>) -> HttpResponse {
+    let jobs = state.jobs.lock().unwrap();
+    match jobs.get(&job_id.into_inner()) {
+        Some(JobState::Pending) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(r#"{"status":"pending"}"#),
+        Some(JobState::Done { post_id }) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(format!(r#"{{"status":"done","post_id":"{}"}}"#, post_id)),
+        Some(JobState::Failed { reason }) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(format!(r#"{{"status":"failed","reason":"{}"}}"#, encode_json_string(reason))),
+        None => HttpResponse::NotFound().body("Unknown job id"),
+    }
+}
+
+struct SavedImage {
+    image_url: String,
+    thumb_url: Option<String>,
+}
+
+// Decodes, validates and persists a single uploaded image field. Returns
+// `None` when the field isn't an image at all. GIF, AVIF and JPEG XL are
+// stored as-is: GIF is decoded for validation and the thumbnail, JPEG XL is
+// checked with `jxl-oxide` and gets no thumbnail, and AVIF is checked by its
+// magic bytes only and gets no thumbnail. Everything else is re-encoded to
+// OUTPUT_EXTENSION. Storage is content-addressed by the stored bytes.
+fn process_image_upload(
+    fname: &str,
+    value: &[u8],
+    digests: &Arc<Mutex<HashMap<String, String>>>,
+) -> Option<Result<SavedImage, String>> {
+    let mime_type = mime_guess::from_path(fname).first_or_octet_stream();
+    if mime_type.type_() != mime::IMAGE {
+        return None;
+    }
+
+    if !matches!(mime_type.subtype().as_ref(), "jpeg" | "jpg" | "png" | "gif" | "webp" | "avif" | "jxl") {
+        return Some(Err("Unsupported image format".to_string()));
+    }
+
+    let extension = mime_type.subtype().as_str();
+
+    if !sniff_matches_extension(value, extension) {
+        return Some(Err("Invalid image file".to_string()));
+    }
+
+    let (stored_bytes, stored_extension, decoded) = if extension == "jxl" {
+        if !validate_jxl(value) {
+            return Some(Err("Invalid image file".to_string()));
+        }
+        (value.to_vec(), "jxl", None)
+    } else if extension == "avif" {
+        (value.to_vec(), "avif", None)
+    } else {
+        let decoded = match image::load_from_memory(value) {
+            Ok(img) => img,
+            Err(_) => return Some(Err("Invalid image file".to_string())),
+        };
+
+        if extension == "gif" {
+            (value.to_vec(), "gif", Some(decoded))
+        } else {
+            match encode_normalized(&decoded) {
+                Ok(bytes) => (bytes, OUTPUT_EXTENSION, Some(decoded)),
+                Err(e) => {
+                    log_error(&format!("Error re-encoding image: {}", e));
+                    return Some(Err("Failed to save image".to_string()));
+                }
+            }
+        }
+    };
+
+    let digest = sha256_hex(&stored_bytes);
+
+    if let Some(image_url) = digests.lock().unwrap().get(&digest).cloned() {
+        let thumb_url = existing_thumb_url(&digest, OUTPUT_EXTENSION);
+        return Some(Ok(SavedImage { image_url, thumb_url }));
+    }
+
+    let sanitized_filename = format!("{}.{}", digest, stored_extension);
+    let filepath = format!("{}{}", IMAGE_UPLOAD_DIR, sanitized_filename);
+
+    if let Err(e) = std::fs::write(&filepath, &stored_bytes) {
+        log_error(&format!("Error writing image: {}", e));
+        return Some(Err("Failed to write image".to_string()));
+    }
+
+    let thumb_url = decoded.as_ref().and_then(|img| generate_thumbnail_from_image(img, &digest));
+    let image_url = format!("/uploads/images/{}", sanitized_filename);
+
+    digests.lock().unwrap().insert(digest, image_url.clone());
+
+    Some(Ok(SavedImage { image_url, thumb_url }))
+}
+
+// Hex-encoded SHA-256 digest, used as the content-addressed storage key.
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// Re-encodes to OUTPUT_EXTENSION at OUTPUT_QUALITY, downscaling first if it
+// exceeds MAX_OUTPUT_DIMENSION. Flattened to RGB first since JPEG has no alpha.
+fn encode_normalized(img: &image::DynamicImage) -> image::ImageResult<Vec<u8>> {
+    let normalized = if img.width().max(img.height()) > MAX_OUTPUT_DIMENSION {
+        img.resize(MAX_OUTPUT_DIMENSION, MAX_OUTPUT_DIMENSION, image::imageops::FilterType::Lanczos3)
+    } else {
+        img.clone()
+    };
+
+    let mut bytes = Vec::new();
+    let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut bytes, OUTPUT_QUALITY);
+    normalized.to_rgb8().write_with_encoder(encoder)?;
+    Ok(bytes)
+}
+
+// Writes a downscaled copy to ./uploads/thumbs/, capped at THUMB_MAX_DIMENSION.
+// Flattened to RGB8 first, same as encode_normalized, since JpegEncoder can't
+// take a 16-bit or alpha-bearing buffer straight from thumbnail().
+fn generate_thumbnail_from_image(img: &image::DynamicImage, digest: &str) -> Option<String> {
+    let thumb = img.thumbnail(THUMB_MAX_DIMENSION, THUMB_MAX_DIMENSION).to_rgb8();
+
+    let thumb_filename = format!("{}.{}", digest, OUTPUT_EXTENSION);
+    let thumb_path = format!("{}{}", THUMB_UPLOAD_DIR, thumb_filename);
+    if let Err(e) = thumb.save(&thumb_path) {
+        log_error(&format!("Error saving thumbnail: {}", e));
+        return None;
+    }
+
+    Some(format!("/uploads/thumbs/{}", thumb_filename))
+}
+
+// Thumbnail URL for a digest that's already stored, skipping regeneration.
+fn existing_thumb_url(digest: &str, extension: &str) -> Option<String> {
+    let thumb_filename = format!("{}.{}", digest, extension);
+    let thumb_path = format!("{}{}", THUMB_UPLOAD_DIR, thumb_filename);
+    if std::path::Path::new(&thumb_path).exists() {
+        Some(format!("/uploads/thumbs/{}", thumb_filename))
+    } else {
+        None
+    }
+}
+
+fn encode_json_string(input: &str) -> String {
+    input.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Sniffs the magic bytes of an uploaded buffer against the extension the
+// client claimed. `image::guess_format` covers everything it decodes; JPEG
+// XL isn't among those, so it's matched against its own signatures instead.
+fn sniff_matches_extension(value: &[u8], extension: &str) -> bool {
+    if extension == "jxl" {
+        return is_jxl_signature(value);
+    }
+
+    match image::guess_format(value) {
+        Ok(format) => matches!(
+            (extension, format),
+            ("jpeg" | "jpg", image::ImageFormat::Jpeg)
+                | ("png", image::ImageFormat::Png)
+                | ("gif", image::ImageFormat::Gif)
+                | ("webp", image::ImageFormat::WebP)
+                | ("avif", image::ImageFormat::Avif)
+        ),
+        Err(_) => false,
+    }
+}
+
+// True if `bytes` starts with a JPEG XL codestream or ISOBMFF container signature.
+fn is_jxl_signature(bytes: &[u8]) -> bool {
+    const CODESTREAM: [u8; 2] = [0xFF, 0x0A];
+    const CONTAINER: [u8; 12] = [0x00, 0x00, 0x00, 0x0C, 0x4A, 0x58, 0x4C, 0x20, 0x0D, 0x0A, 0x87, 0x0A];
+    bytes.starts_with(&CODESTREAM) || bytes.starts_with(&CONTAINER)
+}
+
+// `image` doesn't support JPEG XL, so validation goes through `jxl-oxide`.
+fn validate_jxl(value: &[u8]) -> bool {
+    jxl_oxide::JxlImage::builder().read(std::io::Cursor::new(value)).is_ok()
+}
+
 fn encode_html(input: &str) -> String {
     encode_safe(input).to_string()
 }